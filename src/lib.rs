@@ -0,0 +1,4 @@
+//! Fluentd logger.
+
+pub mod replay;
+pub mod retry_conf;
@@ -1,45 +1,121 @@
 //! Retry sending records configuration.
 
+use std::error::Error as StdError;
+use std::fmt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-/// You can calculate retrying interval as the following equation:
-///
-/// `retry_interval = exp ** (multiplier + retry_counts)`
-///
-/// see: <https://github.com/jimmycuadra/retry/blob/v0.4.0/src/lib.rs#L142-L143>
-///
-/// You can estimate to caluculate with concrete values like:
-///
-/// * `retry_counts`: 10, e ^ (5 + 10)/1000.0/60.0/60.0 = 0.908060381242253, about 0.9 hour
+use rand::Rng;
+
+/// A predicate consulted on every failed send to decide whether it's worth
+/// retrying. Returning `false` aborts the retry loop immediately instead of
+/// backing off and trying again.
+type RetryPredicate = dyn Fn(&dyn StdError) -> bool + Send + Sync;
+
+/// The retry interval for the `n`-th (0-based) attempt is
 ///
-/// * `retry_counts`: 11, e ^ (5 + 11)/1000.0/60.0/60.0 = 2.4683640334744092, about 2.5 hours
+/// `interval = min(max_delay, initial_delay * exp_factor.pow(n))`
 ///
-/// * `retry_counts`: 12, e ^ (5 + 12)/1000.0/60.0/60.0 = 6.709709098215361, about 6.7 hours
+/// so with the default `initial_delay` of 1 second and `exp_factor` of `2`,
+/// attempts wait 1s, 2s, 4s, 8s, 16s, capped at `max_delay` from then on.
+/// `exp_factor == 0` always waits `max_delay`.
 ///
-/// where multiplier = 5,
-/// e is [exponential function](https://doc.rust-lang.org/std/primitive.f64.html#method.exp).
+/// Without a ceiling, that interval would grow unbounded, and because it's
+/// deterministic every client that drops the same connection reconnects in
+/// lockstep. `max_delay` caps the computed interval, and `randomization_factor`
+/// spreads it out by scaling the capped interval by a uniformly random factor
+/// in `[1 - randomization_factor, 1 + randomization_factor]`, the same "full
+/// jitter" approach used by most exponential-backoff clients.
 ///
 /// If you specify `store_file_path`, `fruently` tries to store record(s)
 /// when failing to send into Fluent protocol implemented server.
 ///
 /// ## Default values
 ///
-/// * multiplier: `5_f64`
-/// * max: 10
+/// * `initial_delay`: 1 second
+/// * `exp_factor`: `2`
+/// * max: `Retries::Count(10)`
+/// * `max_total_duration`: None
+/// * `max_delay`: 30 seconds
+/// * `randomization_factor`: `0.25`
 /// * `store_file_path`: None
-#[derive(Debug, Clone, PartialEq)]
+/// * retry predicate: none (retry on every error)
+#[derive(Clone)]
 pub struct RetryConf {
-    max: u64,
-    multiplier: f64,
+    max: Retries,
+    max_total_duration: Option<Duration>,
+    initial_delay: Duration,
+    exp_factor: u32,
+    legacy_exp_multiplier: Option<f64>,
+    max_delay: Duration,
+    randomization_factor: f64,
     store_file_path: Option<PathBuf>,
+    retry_predicate: Option<Arc<RetryPredicate>>,
+}
+
+impl fmt::Debug for RetryConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryConf")
+            .field("max", &self.max)
+            .field("max_total_duration", &self.max_total_duration)
+            .field("initial_delay", &self.initial_delay)
+            .field("exp_factor", &self.exp_factor)
+            .field("legacy_exp_multiplier", &self.legacy_exp_multiplier)
+            .field("max_delay", &self.max_delay)
+            .field("randomization_factor", &self.randomization_factor)
+            .field("store_file_path", &self.store_file_path)
+            .field(
+                "retry_predicate",
+                &self.retry_predicate.as_ref().map(|_| "Fn(&dyn Error) -> bool"),
+            )
+            .finish()
+    }
+}
+
+impl PartialEq for RetryConf {
+    fn eq(&self, other: &RetryConf) -> bool {
+        let predicate_eq = match (&self.retry_predicate, &other.retry_predicate) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+
+        self.max == other.max
+            && self.max_total_duration == other.max_total_duration
+            && self.initial_delay == other.initial_delay
+            && self.exp_factor == other.exp_factor
+            && self.legacy_exp_multiplier == other.legacy_exp_multiplier
+            && self.max_delay == other.max_delay
+            && self.randomization_factor == other.randomization_factor
+            && self.store_file_path == other.store_file_path
+            && predicate_eq
+    }
+}
+
+/// How many times `fruently` should retry sending a record, as set by
+/// [`RetryConf::max`](struct.RetryConf.html#method.max) or
+/// [`RetryConf::max_unlimited`](struct.RetryConf.html#method.max_unlimited).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retries {
+    /// Give up after this many retries; `Count(0)` makes a single attempt.
+    Count(u64),
+    /// Keep retrying indefinitely, subject only to `max_total_duration` if set.
+    Unlimited,
 }
 
 impl Default for RetryConf {
     fn default() -> RetryConf {
         RetryConf {
-            max: 10,
-            multiplier: 5_f64,
+            max: Retries::Count(10),
+            max_total_duration: None,
+            initial_delay: Duration::from_secs(1),
+            exp_factor: 2,
+            legacy_exp_multiplier: None,
+            max_delay: Duration::from_secs(30),
+            randomization_factor: 0.25,
             store_file_path: None,
+            retry_predicate: None,
         }
     }
 }
@@ -49,16 +125,122 @@ impl RetryConf {
         Default::default()
     }
 
+    /// Builds a `RetryConf` using the old `retry_interval = exp(multiplier +
+    /// retry_counts)` formula in milliseconds, for callers that haven't
+    /// migrated to `initial_delay`/`exp_factor` yet. `max_delay` and
+    /// `randomization_factor` still apply on top of it.
+    #[deprecated(
+        since = "0.9.0",
+        note = "use `RetryConf::new` with `initial_delay`/`exp_factor` instead"
+    )]
+    pub fn with_legacy_exp_backoff(multiplier: f64) -> RetryConf {
+        RetryConf {
+            legacy_exp_multiplier: Some(multiplier),
+            ..Default::default()
+        }
+    }
+
     pub fn max(mut self, max: u64) -> RetryConf {
-        self.max = max;
+        self.max = Retries::Count(max);
         self
     }
 
+    /// Retries forever instead of giving up after a fixed count. Still
+    /// subject to `max_total_duration` if one is set.
+    pub fn max_unlimited(mut self) -> RetryConf {
+        self.max = Retries::Unlimited;
+        self
+    }
+
+    /// Caps the *total* wall-clock time spent retrying, across all attempts,
+    /// regardless of how many retries `max` still allows. Once exceeded, the
+    /// retry loop aborts even under `Retries::Unlimited`.
+    pub fn max_total_duration(mut self, max_total_duration: Duration) -> RetryConf {
+        self.max_total_duration = Some(max_total_duration);
+        self
+    }
+
+    /// Returns the configured retry count limit.
+    pub fn retries(&self) -> Retries {
+        self.max
+    }
+
+    /// Returns the configured total-time retry budget, if any.
+    pub fn total_duration(&self) -> Option<Duration> {
+        self.max_total_duration
+    }
+
+    /// Whether the retry loop should stop given how many retries have been
+    /// made and how much time has elapsed so far, i.e. whichever of the
+    /// retry count or the total-time budget is hit first.
+    pub fn is_exhausted(&self, retry_count: u64, elapsed: Duration) -> bool {
+        let count_exhausted = match self.max {
+            Retries::Count(max) => retry_count >= max,
+            Retries::Unlimited => false,
+        };
+        let time_exhausted = self
+            .max_total_duration
+            .is_some_and(|budget| elapsed >= budget);
+
+        count_exhausted || time_exhausted
+    }
+
+    /// Sets the wait before the first retry (`retry_count == 0`). Subsequent
+    /// retries scale this by `exp_factor`.
+    pub fn initial_delay(mut self, initial_delay: Duration) -> RetryConf {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// Sets the base the retry interval is raised to the power of
+    /// `retry_count`. `0` means every retry waits `max_delay`.
+    pub fn exp_factor(mut self, exp_factor: u32) -> RetryConf {
+        self.exp_factor = exp_factor;
+        self
+    }
+
+    #[deprecated(
+        since = "0.9.0",
+        note = "use `RetryConf::with_legacy_exp_backoff` instead"
+    )]
     pub fn multiplier(mut self, multiplier: f64) -> RetryConf {
-        self.multiplier = multiplier;
+        self.legacy_exp_multiplier = Some(multiplier);
+        self
+    }
+
+    /// Caps the retry interval computed by [`interval_at`](#method.interval_at)
+    /// so it never waits longer than `max_delay`, regardless of `retry_count`.
+    pub fn max_delay(mut self, max_delay: Duration) -> RetryConf {
+        self.max_delay = max_delay;
         self
     }
 
+    /// Sets how much the capped retry interval is randomized, as a fraction
+    /// of itself. `0.25` means the effective delay is scaled by a factor
+    /// uniformly chosen from `[0.75, 1.25]`. Clamped to `[0.0, 1.0]`.
+    pub fn jitter(mut self, randomization_factor: f64) -> RetryConf {
+        self.randomization_factor = randomization_factor.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Attaches a predicate that decides, on each failed send, whether the
+    /// error is worth retrying. Returning `false` aborts the retry loop
+    /// immediately instead of backing off and trying again. With no
+    /// predicate set, every error is retried, matching prior behavior.
+    pub fn retry_if(
+        mut self,
+        f: impl Fn(&dyn StdError) -> bool + Send + Sync + 'static,
+    ) -> RetryConf {
+        self.retry_predicate = Some(Arc::new(f));
+        self
+    }
+
+    /// Whether `err` should be retried, per the predicate set by
+    /// [`retry_if`](#method.retry_if). With no predicate set, always `true`.
+    pub fn should_retry(&self, err: &dyn StdError) -> bool {
+        self.retry_predicate.as_ref().is_none_or(|f| f(err))
+    }
+
     pub fn store_file(mut self, path: PathBuf) -> RetryConf {
         self.store_file_path = Some(path);
         self
@@ -72,7 +254,142 @@ impl RetryConf {
         self.store_file_path
     }
 
+    #[deprecated(since = "0.9.0", note = "use `RetryConf::interval_at` instead")]
     pub fn build(self) -> (u64, f64) {
-        (self.max, self.multiplier)
+        let max = match self.max {
+            Retries::Count(max) => max,
+            Retries::Unlimited => u64::MAX,
+        };
+
+        (max, self.legacy_exp_multiplier.unwrap_or(5.0))
+    }
+
+    /// Computes the wait before the next attempt for a given 0-based
+    /// `retry_count`, applying both the `max_delay` ceiling and jitter.
+    pub fn interval_at(&self, retry_count: u64) -> Duration {
+        let capped = match self.legacy_exp_multiplier {
+            Some(multiplier) => {
+                let base_millis = (multiplier + retry_count as f64).exp();
+                let base = Duration::from_millis(base_millis.max(0.0) as u64);
+                if base > self.max_delay {
+                    self.max_delay
+                } else {
+                    base
+                }
+            }
+            None if self.exp_factor == 0 => self.max_delay,
+            None => {
+                // `exp_factor.powi(n)` overflows to infinity for large `n`, and
+                // `Duration::from_secs_f64` panics on infinite input, so the cap
+                // must be applied in `f64` space before the `Duration` is built.
+                let factor = (self.exp_factor as f64).powi(retry_count as i32);
+                let secs =
+                    (self.initial_delay.as_secs_f64() * factor).min(self.max_delay.as_secs_f64());
+                Duration::from_secs_f64(secs)
+            }
+        };
+
+        self.jittered(capped)
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        let low = 1.0 - self.randomization_factor;
+        let high = 1.0 + self.randomization_factor;
+        let factor = if low < high {
+            rand::thread_rng().gen_range(low..high)
+        } else {
+            1.0
+        };
+
+        Duration::from_secs_f64(delay.as_secs_f64() * factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    #[allow(deprecated)]
+    fn build_keeps_the_old_default_multiplier_for_back_compat() {
+        assert_eq!(RetryConf::new().build(), (10, 5.0));
+    }
+
+    #[test]
+    fn max_delay_caps_interval_at_large_retry_counts() {
+        let conf = RetryConf::new()
+            .initial_delay(Duration::from_millis(1))
+            .exp_factor(2)
+            .max_delay(Duration::from_millis(50))
+            .jitter(0.0);
+
+        assert_eq!(conf.interval_at(20), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn interval_at_never_panics_even_once_the_exponent_overflows() {
+        let conf = RetryConf::new().max_unlimited();
+        assert!(conf.interval_at(10_000) <= Duration::from_secs(38));
+    }
+
+    #[test]
+    fn jitter_clamps_randomization_factor_to_unit_range() {
+        let no_spread = RetryConf::new()
+            .max_delay(Duration::from_millis(50))
+            .jitter(-1.0);
+        for retry_count in 0..5 {
+            assert_eq!(
+                no_spread.interval_at(retry_count),
+                Duration::from_millis(50)
+            );
+        }
+
+        let full_spread = RetryConf::new()
+            .max_delay(Duration::from_millis(50))
+            .jitter(5.0);
+        for retry_count in 0..20 {
+            assert!(full_spread.interval_at(retry_count) <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn is_exhausted_stops_at_the_configured_count() {
+        let conf = RetryConf::new().max(3);
+        assert!(!conf.is_exhausted(2, Duration::from_secs(0)));
+        assert!(conf.is_exhausted(3, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn max_zero_means_no_retries() {
+        let conf = RetryConf::new().max(0);
+        assert!(conf.is_exhausted(0, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn max_unlimited_retries_until_the_time_budget_expires() {
+        let conf = RetryConf::new()
+            .max_unlimited()
+            .max_total_duration(Duration::from_secs(60));
+
+        assert!(!conf.is_exhausted(1_000_000, Duration::from_secs(59)));
+        assert!(conf.is_exhausted(1_000_000, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn should_retry_defaults_to_true_with_no_predicate() {
+        let conf = RetryConf::new();
+        let err = io::Error::other("boom");
+        assert!(conf.should_retry(&err));
+    }
+
+    #[test]
+    fn retry_if_can_reject_a_permanent_error() {
+        let conf = RetryConf::new().retry_if(|err| err.to_string() != "permanent");
+        let transient = io::Error::other("transient");
+        let permanent = io::Error::other("permanent");
+
+        assert!(conf.should_retry(&transient));
+        assert!(!conf.should_retry(&permanent));
     }
 }